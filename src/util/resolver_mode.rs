@@ -11,13 +11,24 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::collections::{HashMap, HashSet};
 use std::num::ParseIntError;
 use std::ops::Range;
 use std::str::FromStr;
 
-use bitcoin::secp256k1::rand::{rngs::ThreadRng, thread_rng, RngCore};
+use bitcoin::secp256k1::rand::{rngs::ThreadRng, thread_rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use wallet::bip32::{ChildIndex, UnhardenedIndex};
 
+/// Size of the unhardened index space (`2^31`), i.e. the number of distinct
+/// values an [`UnhardenedIndex`] can represent.
+const UNHARDENED_SPACE: u32 = 0x8000_0000;
+
+/// Counts at or above this fraction of [`UNHARDENED_SPACE`] switch the no-repeat
+/// sampler from a retry-on-collision set to a partial Fisher–Yates shuffle,
+/// since collisions become too frequent for retrying to stay cheap.
+const SHUFFLE_THRESHOLD: u32 = UNHARDENED_SPACE / 4;
+
 #[derive(Clone, PartialEq, Eq, Debug, Display, From, Error)]
 #[display(doc_comments)]
 pub enum ParseError {
@@ -29,20 +40,118 @@ pub enum ParseError {
     /// which can't be used in the current context
     HardenedIndex,
 
+    /// The provided random seed is not a valid 32-byte hex string
+    InvalidSeed,
+
+    /// Range start {0} must be strictly less than its end {1}
+    InvertedRange(u32, u32),
+
     /// Unrecognized resolver mode name {0}
     UnrecognizedTypeName(String),
 }
 
+/// Default BIP-44 gap limit: the number of consecutive unused addresses
+/// that must be observed before a [`ResolverModeType::While`] scan stops.
+const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// The gap limit driving a [`ResolverModeType::While`] scan, displayed and
+/// parsed as a bare integer appended to `while`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct GapLimit(pub u32);
+
+impl Default for GapLimit {
+    fn default() -> Self {
+        GapLimit(DEFAULT_GAP_LIMIT)
+    }
+}
+
+impl std::fmt::Display for GapLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Display)]
 pub enum ResolverModeType {
-    #[display("while")]
-    While,
+    #[display("while{0}")]
+    While(GapLimit),
 
     #[display("first{0}")]
     First(UnhardenedIndex),
 
-    #[display("random{0}")]
-    Random(UnhardenedIndex),
+    #[display("random{0}{1}")]
+    Random(UnhardenedIndex, NoRepeat),
+
+    /// A seeded variant of [`ResolverModeType::Random`] which replays the
+    /// same sequence of indexes on every run, making random scans
+    /// reproducible for debugging and regression tests.
+    #[display("random{0}/{1}{2}")]
+    SeededRandom(UnhardenedIndex, RandomSeed, NoRepeat),
+
+    /// An explicit, inclusive-exclusive range of indexes, letting a caller
+    /// resume a scan from a checkpoint or shard a large derivation range
+    /// across several workers.
+    #[display("range{0}-{1}")]
+    Range(UnhardenedIndex, UnhardenedIndex),
+}
+
+/// Marker appended to a [`ResolverModeType::Random`] or
+/// [`ResolverModeType::SeededRandom`] directive (as a trailing `!`) that
+/// requests sampling without replacement, i.e. `count` pairwise-distinct
+/// indexes rather than `count` independent draws.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct NoRepeat(pub bool);
+
+impl std::fmt::Display for NoRepeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0 {
+            f.write_str("!")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A 32-byte seed used to make [`ResolverModeType::SeededRandom`]
+/// deterministic, displayed and parsed as a 64-character hex string.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct RandomSeed([u8; 32]);
+
+impl From<[u8; 32]> for RandomSeed {
+    fn from(seed: [u8; 32]) -> Self {
+        RandomSeed(seed)
+    }
+}
+
+impl From<RandomSeed> for [u8; 32] {
+    fn from(seed: RandomSeed) -> Self {
+        seed.0
+    }
+}
+
+impl FromStr for RandomSeed {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::InvalidSeed);
+        }
+        let mut seed = [0u8; 32];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| ParseError::InvalidSeed)?;
+        }
+        Ok(RandomSeed(seed))
+    }
+}
+
+impl std::fmt::Display for RandomSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
 }
 
 impl FromStr for ResolverModeType {
@@ -59,16 +168,45 @@ impl FromStr for ResolverModeType {
                 )
             }
         } else if let Some(s) = s.strip_prefix("random") {
+            let (s, no_repeat) = match s.strip_suffix('!') {
+                Some(s) => (s, NoRepeat(true)),
+                None => (s, NoRepeat(false)),
+            };
             if s.is_empty() {
-                ResolverModeType::Random(UnhardenedIndex::one())
+                ResolverModeType::Random(UnhardenedIndex::one(), no_repeat)
+            } else if let Some((count, seed)) = s.split_once('/') {
+                ResolverModeType::SeededRandom(
+                    UnhardenedIndex::from_index(u32::from_str(count)?)
+                        .map_err(|_| ParseError::HardenedIndex)?,
+                    RandomSeed::from_str(seed)?,
+                    no_repeat,
+                )
             } else {
                 ResolverModeType::Random(
                     UnhardenedIndex::from_index(u32::from_str(s)?)
                         .map_err(|_| ParseError::HardenedIndex)?,
+                    no_repeat,
                 )
             }
-        } else if s == "while" {
-            ResolverModeType::While
+        } else if let Some(s) = s.strip_prefix("while") {
+            if s.is_empty() {
+                ResolverModeType::While(GapLimit::default())
+            } else {
+                ResolverModeType::While(GapLimit(u32::from_str(s)?))
+            }
+        } else if let Some(s) = s.strip_prefix("range") {
+            let (start, end) = s
+                .split_once('-')
+                .ok_or_else(|| ParseError::UnrecognizedTypeName(format!("range{}", s)))?;
+            let start = u32::from_str(start)?;
+            let end = u32::from_str(end)?;
+            if start >= end {
+                return Err(ParseError::InvertedRange(start, end));
+            }
+            ResolverModeType::Range(
+                UnhardenedIndex::from_index(start).map_err(|_| ParseError::HardenedIndex)?,
+                UnhardenedIndex::from_index(end).map_err(|_| ParseError::HardenedIndex)?,
+            )
         } else {
             return Err(ParseError::UnrecognizedTypeName(s.to_owned()));
         })
@@ -78,57 +216,424 @@ impl FromStr for ResolverModeType {
 impl ResolverModeType {
     pub fn count(self) -> usize {
         match self {
-            ResolverModeType::While => 1usize,
+            ResolverModeType::While(gap_limit) => gap_limit.0 as usize,
             ResolverModeType::First(count) => u32::from(count) as usize,
-            ResolverModeType::Random(count) => u32::from(count) as usize,
+            ResolverModeType::Random(count, _) => u32::from(count) as usize,
+            ResolverModeType::SeededRandom(count, ..) => u32::from(count) as usize,
+            ResolverModeType::Range(start, end) => {
+                (u32::from(end).saturating_sub(u32::from(start))) as usize
+            }
         }
     }
 
     pub fn range(self) -> Range<u32> {
-        0u32..(self.count() as u32)
+        match self {
+            ResolverModeType::Range(start, end) => u32::from(start)..u32::from(end),
+            _ => 0u32..(self.count() as u32),
+        }
     }
 
     pub fn is_while(self) -> bool {
-        self == ResolverModeType::While
+        matches!(self, ResolverModeType::While(_))
     }
     pub fn is_random(self) -> bool {
-        matches!(self, ResolverModeType::Random(_))
+        matches!(
+            self,
+            ResolverModeType::Random(..) | ResolverModeType::SeededRandom(..)
+        )
     }
+
+    pub fn no_repeat(self) -> bool {
+        match self {
+            ResolverModeType::Random(_, no_repeat) => no_repeat.0,
+            ResolverModeType::SeededRandom(_, _, no_repeat) => no_repeat.0,
+            _ => false,
+        }
+    }
+}
+
+/// Source of randomness backing a [`ResolverModeIter`]: either the thread-
+/// local RNG used by the plain `random<N>` mode, or a seeded, deterministic
+/// RNG used by `random<N>/<seed>` so that scans can be replayed. `ChaCha20`
+/// is used (rather than `StdRng`) because its algorithm is fixed by
+/// specification, so a stored seed keeps reproducing the same sequence
+/// across `rand` upgrades, unlike `StdRng`'s unspecified, version-dependent
+/// generator.
+enum RandomSource {
+    Thread(ThreadRng),
+    Seeded(ChaCha20Rng),
+}
+
+impl RandomSource {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            RandomSource::Thread(rng) => rng.next_u32(),
+            RandomSource::Seeded(rng) => rng.next_u32(),
+        }
+    }
+}
+
+/// Draws indexes without replacement, i.e. guarantees that no value is
+/// produced twice over the lifetime of a [`ResolverModeIter`].
+///
+/// For counts that are a small fraction of [`UNHARDENED_SPACE`], collisions
+/// are rare, so we simply re-draw on collision, tracking what has already
+/// been emitted in a [`HashSet`]. For counts approaching the whole space,
+/// retrying becomes expensive as the space fills up, so we instead run a
+/// partial Fisher-Yates shuffle over a bounded window, using a sparse
+/// [`HashMap`] in place of a fully materialized `2^31`-element array.
+enum NoRepeatSampler {
+    Retry(HashSet<u32>),
+    Shuffle {
+        positions: HashMap<u32, u32>,
+        next: u32,
+    },
+}
+
+impl NoRepeatSampler {
+    fn new(count: u32) -> Self {
+        if count < SHUFFLE_THRESHOLD {
+            NoRepeatSampler::Retry(HashSet::with_capacity(count as usize))
+        } else {
+            NoRepeatSampler::Shuffle {
+                positions: HashMap::new(),
+                next: 0,
+            }
+        }
+    }
+
+    fn draw(&mut self, rand: &mut RandomSource) -> u32 {
+        match self {
+            NoRepeatSampler::Retry(seen) => loop {
+                let candidate = rand.next_u32() & (UNHARDENED_SPACE - 1);
+                if seen.insert(candidate) {
+                    return candidate;
+                }
+            },
+            NoRepeatSampler::Shuffle { positions, next } => {
+                let span = UNHARDENED_SPACE - *next;
+                let pick = *next + rand.next_u32() % span;
+                let picked_value = *positions.get(&pick).unwrap_or(&pick);
+                let next_value = *positions.get(next).unwrap_or(next);
+                positions.insert(pick, next_value);
+                positions.insert(*next, picked_value);
+                *next += 1;
+                picked_value
+            }
+        }
+    }
+}
+
+/// An item produced by [`ResolverModeIter`]: the sequential position of the
+/// draw (`offset`, starting from the mode's range start) paired with the
+/// derivation `index` it resolved to. Keeping both lets a caller persist
+/// `offset` as scan-progress checkpoint while deriving keys from `index`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ResolverModeItem {
+    pub offset: u32,
+    pub index: u32,
 }
 
 pub struct ResolverModeIter {
     mode: ResolverModeType,
-    rand: ThreadRng,
+    rand: RandomSource,
+    sampler: Option<NoRepeatSampler>,
     offset: u32,
 }
 
 impl IntoIterator for ResolverModeType {
-    type Item = u32;
+    type Item = ResolverModeItem;
     type IntoIter = ResolverModeIter;
 
     fn into_iter(self) -> Self::IntoIter {
+        let rand = match self {
+            ResolverModeType::SeededRandom(_, seed, _) => {
+                RandomSource::Seeded(ChaCha20Rng::from_seed(seed.into()))
+            }
+            _ => RandomSource::Thread(thread_rng()),
+        };
+        let sampler = self
+            .no_repeat()
+            .then(|| NoRepeatSampler::new(self.count() as u32));
         ResolverModeIter {
             mode: self,
-            rand: thread_rng(),
+            rand,
+            sampler,
             offset: self.range().start,
         }
     }
 }
 
 impl Iterator for ResolverModeIter {
-    type Item = u32;
+    type Item = ResolverModeItem;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.offset >= self.mode.range().end {
-            None
+            return None;
+        }
+        let offset = self.offset;
+        let index = if self.mode.is_random() {
+            match &mut self.sampler {
+                Some(sampler) => sampler.draw(&mut self.rand),
+                None => self.rand.next_u32() & (UNHARDENED_SPACE - 1),
+            }
         } else {
-            let index = if self.mode.is_random() {
-                self.rand.next_u32()
+            offset
+        };
+        self.offset += 1;
+        Some(ResolverModeItem { offset, index })
+    }
+}
+
+/// Error surfaced by an [`AddressResolver`] when address-usage information
+/// cannot be retrieved from its backend.
+#[derive(Clone, PartialEq, Eq, Debug, Display, From, Error)]
+#[display(doc_comments)]
+pub enum ResolverError {
+    /// Unable to query address usage from the backend: {0}
+    Backend(String),
+
+    /// Scanned the entire unhardened index space without observing {0}
+    /// consecutive unused addresses; the wallet may be exhausted, or the
+    /// gap limit is misconfigured
+    SpaceExhausted(u32),
+}
+
+/// A pluggable source of address-usage information, used to drive the
+/// gap-limit walk behind [`ResolverModeType::While`]. Resolvers are passed
+/// in by the caller rather than hard-required by this module, so an
+/// application can back them with an Electrum connection, an Esplora
+/// client, or anything else that can answer "has this index been used".
+pub trait AddressResolver {
+    fn is_used(&self, index: UnhardenedIndex) -> Result<bool, ResolverError>;
+}
+
+/// Outcome of a [`ResolverModeIter::while_with`] gap-limit walk: every index
+/// found to be used, and the next fresh address -- the lowest-index unused
+/// address seen over the whole walk, not merely the start of the
+/// terminating gap run (an earlier, isolated unused index surrounded by
+/// used ones is still the wallet's next fresh address).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GapLimitScan {
+    pub used: HashSet<UnhardenedIndex>,
+    pub next_unused: UnhardenedIndex,
+}
+
+impl ResolverModeIter {
+    /// Drives a BIP-44 gap-limit scan with `resolver`, starting at this
+    /// iterator's current offset and walking consecutive unhardened indexes
+    /// until `gap_limit` addresses in a row come back unused. Meaningful
+    /// only when the underlying mode is [`ResolverModeType::While`]; other
+    /// modes fall back to [`GapLimit::default`].
+    pub fn while_with(
+        mut self,
+        resolver: &impl AddressResolver,
+    ) -> Result<GapLimitScan, ResolverError> {
+        let gap_limit = match self.mode {
+            ResolverModeType::While(gap_limit) => gap_limit,
+            _ => GapLimit::default(),
+        }
+        .0;
+
+        let mut used = HashSet::new();
+        let mut lowest_unused = None;
+        let mut gap = 0u32;
+        loop {
+            if self.offset >= UNHARDENED_SPACE {
+                return Err(ResolverError::SpaceExhausted(gap_limit));
+            }
+            let index = UnhardenedIndex::from_index(self.offset)
+                .expect("offset bounds-checked against UNHARDENED_SPACE above");
+            if resolver.is_used(index)? {
+                used.insert(index);
+                gap = 0;
             } else {
-                self.offset
-            };
+                if lowest_unused.is_none() {
+                    lowest_unused = Some(index);
+                }
+                gap += 1;
+                if gap >= gap_limit {
+                    return Ok(GapLimitScan {
+                        used,
+                        next_unused: lowest_unused
+                            .expect("set as soon as the first unused index is seen"),
+                    });
+                }
+            }
             self.offset += 1;
-            Some(index)
         }
     }
 }
+
+/// [`AddressResolver`] implementations backed by concrete blockchain
+/// clients. Neither backend is required by [`While`](ResolverModeType::While)
+/// itself -- enable whichever feature matches your deployment, or implement
+/// [`AddressResolver`] directly against another data source entirely.
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+pub mod resolvers {
+    use super::{AddressResolver, ResolverError, UnhardenedIndex};
+
+    /// [`AddressResolver`] backed by an Electrum server connection. The
+    /// caller supplies `script_of` to derive the scriptPubkey to query for
+    /// a given index, keeping this resolver independent of any particular
+    /// descriptor or derivation scheme.
+    #[cfg(feature = "electrum")]
+    pub struct ElectrumResolver<'c, F> {
+        client: &'c electrum_client::Client,
+        script_of: F,
+    }
+
+    #[cfg(feature = "electrum")]
+    impl<'c, F> ElectrumResolver<'c, F>
+    where
+        F: Fn(UnhardenedIndex) -> bitcoin::Script,
+    {
+        pub fn new(client: &'c electrum_client::Client, script_of: F) -> Self {
+            ElectrumResolver { client, script_of }
+        }
+    }
+
+    #[cfg(feature = "electrum")]
+    impl<'c, F> AddressResolver for ElectrumResolver<'c, F>
+    where
+        F: Fn(UnhardenedIndex) -> bitcoin::Script,
+    {
+        fn is_used(&self, index: UnhardenedIndex) -> Result<bool, ResolverError> {
+            use electrum_client::ElectrumApi;
+            let script = (self.script_of)(index);
+            self.client
+                .script_get_history(&script)
+                .map(|history| !history.is_empty())
+                .map_err(|err| ResolverError::Backend(err.to_string()))
+        }
+    }
+
+    /// [`AddressResolver`] backed by an Esplora HTTP client, in the same
+    /// spirit as [`ElectrumResolver`] but querying a block explorer's REST
+    /// API instead of the Electrum protocol.
+    #[cfg(feature = "esplora")]
+    pub struct EsploraResolver<'c, F> {
+        client: &'c esplora_client::BlockingClient,
+        script_of: F,
+    }
+
+    #[cfg(feature = "esplora")]
+    impl<'c, F> EsploraResolver<'c, F>
+    where
+        F: Fn(UnhardenedIndex) -> bitcoin::Script,
+    {
+        pub fn new(client: &'c esplora_client::BlockingClient, script_of: F) -> Self {
+            EsploraResolver { client, script_of }
+        }
+    }
+
+    #[cfg(feature = "esplora")]
+    impl<'c, F> AddressResolver for EsploraResolver<'c, F>
+    where
+        F: Fn(UnhardenedIndex) -> bitcoin::Script,
+    {
+        fn is_used(&self, index: UnhardenedIndex) -> Result<bool, ResolverError> {
+            let script = (self.script_of)(index);
+            self.client
+                .scripthash_txs(&script, None)
+                .map(|txs| !txs.is_empty())
+                .map_err(|err| ResolverError::Backend(err.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SEED: &str = "00000000000000000000000000000000000000000000000000000000000000ff";
+
+    #[test]
+    fn seeded_random_round_trips() {
+        let mode = format!("random5/{}", SEED);
+        let parsed = ResolverModeType::from_str(&mode).unwrap();
+        assert_eq!(
+            parsed,
+            ResolverModeType::SeededRandom(
+                UnhardenedIndex::from_index(5).unwrap(),
+                RandomSeed::from_str(SEED).unwrap(),
+                NoRepeat(false),
+            )
+        );
+        assert_eq!(parsed.to_string(), mode);
+    }
+
+    #[test]
+    fn no_repeat_random_round_trips() {
+        let parsed = ResolverModeType::from_str("random5!").unwrap();
+        assert_eq!(
+            parsed,
+            ResolverModeType::Random(UnhardenedIndex::from_index(5).unwrap(), NoRepeat(true))
+        );
+        assert_eq!(parsed.to_string(), "random5!");
+    }
+
+    #[test]
+    fn range_round_trips_and_has_correct_span() {
+        let parsed = ResolverModeType::from_str("range10-20").unwrap();
+        assert_eq!(
+            parsed,
+            ResolverModeType::Range(
+                UnhardenedIndex::from_index(10).unwrap(),
+                UnhardenedIndex::from_index(20).unwrap(),
+            )
+        );
+        assert_eq!(parsed.to_string(), "range10-20");
+        assert_eq!(parsed.count(), 10);
+        assert_eq!(parsed.range(), 10u32..20u32);
+    }
+
+    #[test]
+    fn reversed_range_is_rejected() {
+        assert_eq!(
+            ResolverModeType::from_str("range20-10"),
+            Err(ParseError::InvertedRange(20, 10))
+        );
+        assert_eq!(
+            ResolverModeType::from_str("range10-10"),
+            Err(ParseError::InvertedRange(10, 10))
+        );
+    }
+
+    #[test]
+    fn seeded_random_is_reproducible() {
+        let mode = ResolverModeType::SeededRandom(
+            UnhardenedIndex::from_index(50).unwrap(),
+            RandomSeed::from_str(SEED).unwrap(),
+            NoRepeat(false),
+        );
+        let first: Vec<u32> = mode.into_iter().map(|item| item.index).collect();
+        let second: Vec<u32> = mode.into_iter().map(|item| item.index).collect();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn random_draws_never_set_the_hardened_bit() {
+        let mode = ResolverModeType::SeededRandom(
+            UnhardenedIndex::from_index(1000).unwrap(),
+            RandomSeed::from_str(SEED).unwrap(),
+            NoRepeat(false),
+        );
+        for item in mode.into_iter() {
+            assert_eq!(item.index & 0x8000_0000, 0);
+        }
+    }
+
+    #[test]
+    fn no_repeat_draws_are_distinct() {
+        let mode = ResolverModeType::SeededRandom(
+            UnhardenedIndex::from_index(500).unwrap(),
+            RandomSeed::from_str(SEED).unwrap(),
+            NoRepeat(true),
+        );
+        let indexes: Vec<u32> = mode.into_iter().map(|item| item.index).collect();
+        let unique: HashSet<u32> = indexes.iter().copied().collect();
+        assert_eq!(indexes.len(), unique.len());
+        assert_eq!(indexes.len(), 500);
+    }
+}